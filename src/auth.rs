@@ -0,0 +1,161 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{Error, Result};
+use crate::AppState;
+
+/// Auth configuration loaded from the environment at startup.
+#[derive(Clone)]
+pub struct Config {
+    pub jwt_secret: String,
+    pub jwt_maxage: i64,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            jwt_secret: std::env::var("JWT_SECRET").expect("JWT_SECRET is not set"),
+            jwt_maxage: std::env::var("JWT_MAXAGE")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(3600),
+        }
+    }
+}
+
+/// Claims carried by every issued token.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub iat: usize,
+    pub exp: usize,
+}
+
+/// The authenticated user id injected into request extensions by [`auth`].
+#[derive(Debug, Clone, Copy)]
+pub struct AuthUser(pub i64);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Credentials {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenResponse {
+    pub token: String,
+}
+
+/// Sign a token for `user_id`, expiring `jwt_maxage` seconds from now.
+fn issue_token(config: &Config, user_id: i64) -> Result<String> {
+    let now = jsonwebtoken::get_current_timestamp() as usize;
+
+    let claims = Claims {
+        sub: user_id.to_string(),
+        iat: now,
+        exp: now + config.jwt_maxage as usize,
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+    )
+    .map_err(|err| Error::Other(err.to_string()))
+}
+
+pub async fn register(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> Result<Json<TokenResponse>> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    let password_hash = Argon2::default()
+        .hash_password(credentials.password.as_bytes(), &salt)
+        .map_err(|err| Error::Other(err.to_string()))?
+        .to_string();
+
+    let user_id = sqlx::query_scalar!(
+        r#"
+        insert into users (email, password_hash)
+        values ($1, $2)
+        returning id
+        "#,
+        credentials.email,
+        password_hash
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    tracing::debug!("Registered user {}", credentials.email);
+
+    Ok(Json(TokenResponse {
+        token: issue_token(&state.config, user_id)?,
+    }))
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> Result<Json<TokenResponse>> {
+    let user = sqlx::query!(
+        "select id, password_hash from users where email = $1",
+        credentials.email
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::Unauthorized)?;
+
+    let parsed_hash =
+        PasswordHash::new(&user.password_hash).map_err(|err| Error::Other(err.to_string()))?;
+
+    Argon2::default()
+        .verify_password(credentials.password.as_bytes(), &parsed_hash)
+        .map_err(|_| Error::Unauthorized)?;
+
+    tracing::debug!("User {} logged in", credentials.email);
+
+    Ok(Json(TokenResponse {
+        token: issue_token(&state.config, user.id)?,
+    }))
+}
+
+/// Middleware guarding link creation, updates and statistics.
+///
+/// Parses the `Authorization: Bearer` header, validates the token against the
+/// configured secret and expiry, and stashes the resolved [`AuthUser`] in the
+/// request extensions so downstream handlers can attribute work to an owner.
+pub async fn auth(State(state): State<AppState>, mut request: Request, next: Next) -> Result<Response> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+
+    let decoded = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(state.config.jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map_err(|_| Error::Unauthorized)?;
+
+    let user_id: i64 = decoded.claims.sub.parse().map_err(|_| Error::Unauthorized)?;
+
+    request.extensions_mut().insert(AuthUser(user_id));
+
+    Ok(next.run(request).await)
+}
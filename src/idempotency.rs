@@ -0,0 +1,164 @@
+use axum::{
+    body::{to_bytes, Body},
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::Response,
+};
+use sqlx::PgPool;
+
+use crate::utils::{Error, Result};
+
+/// A single captured response header.
+///
+/// Mirrors the `header_pair` composite type in Postgres so a replayed response
+/// is byte-for-byte identical to the one we originally sent — values are stored
+/// as `bytea` because header values are not guaranteed to be valid UTF-8.
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+pub struct HeaderPair {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// A response previously persisted against an idempotency key.
+pub struct StoredResponse {
+    pub response_status_code: Option<i16>,
+    pub response_headers: Option<Vec<HeaderPair>>,
+    pub response_body: Option<Vec<u8>>,
+}
+
+/// Claim an idempotency key before running the handler.
+///
+/// Inserts a pending row with `ON CONFLICT DO NOTHING`; if we won the insert we
+/// return `Ok(None)` and the caller proceeds. If the key already exists we
+/// re-read it: a completed row is returned for replay, while a row that is still
+/// pending means a concurrent retry is in flight and we surface a `409`.
+pub async fn begin(pool: &PgPool, owner_id: i64, key: &str) -> Result<Option<StoredResponse>> {
+    let claimed = sqlx::query_scalar!(
+        r#"
+        insert into idempotency (owner_id, idempotency_key)
+        values ($1, $2)
+        on conflict (owner_id, idempotency_key) do nothing
+        returning idempotency_key
+        "#,
+        owner_id,
+        key
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if claimed.is_some() {
+        return Ok(None);
+    }
+
+    let stored = sqlx::query_as!(
+        StoredResponse,
+        r#"
+        select
+            response_status_code,
+            response_headers as "response_headers: Vec<HeaderPair>",
+            response_body
+        from idempotency
+        where owner_id = $1 and idempotency_key = $2
+        "#,
+        owner_id,
+        key
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if stored.response_status_code.is_none() {
+        return Err(Error::IdempotencyConflict);
+    }
+
+    Ok(Some(stored))
+}
+
+/// Persist a freshly produced response and hand back an equivalent one.
+///
+/// The incoming `Response` is consumed to read its body, so we rebuild it from
+/// the captured parts before returning it to the caller.
+pub async fn save(pool: &PgPool, owner_id: i64, key: &str, response: Response) -> Result<Response> {
+    let (parts, body) = response.into_parts();
+
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|err| Error::Other(err.to_string()))?
+        .to_vec();
+
+    let headers: Vec<HeaderPair> = parts
+        .headers
+        .iter()
+        .map(|(name, value)| HeaderPair {
+            name: name.as_str().to_string(),
+            value: value.as_bytes().to_vec(),
+        })
+        .collect();
+
+    sqlx::query!(
+        r#"
+        update idempotency
+        set response_status_code = $3,
+            response_headers = $4,
+            response_body = $5
+        where owner_id = $1 and idempotency_key = $2
+        "#,
+        owner_id,
+        key,
+        parts.status.as_u16() as i16,
+        &headers,
+        &body
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Release a key claimed by [`begin`] whose handler never produced a response.
+///
+/// Only pending rows (`response_status_code is null`) are removed, so a key that
+/// already captured a real response is never clobbered. Dropping the claim lets a
+/// later retry of the same key start over instead of being wedged on a permanent
+/// `409`. Cleanup failures are logged rather than propagated — the original
+/// handler error is what the caller cares about.
+pub async fn abort(pool: &PgPool, owner_id: i64, key: &str) {
+    let result = sqlx::query!(
+        r#"
+        delete from idempotency
+        where owner_id = $1
+          and idempotency_key = $2
+          and response_status_code is null
+        "#,
+        owner_id,
+        key
+    )
+    .execute(pool)
+    .await;
+
+    if let Err(err) = result {
+        tracing::error!("Failed to release pending idempotency key {}: {}", key, err);
+    }
+}
+
+/// Reconstruct a `Response` from a stored row for replay.
+pub fn replay(stored: StoredResponse) -> Result<Response> {
+    let status = stored
+        .response_status_code
+        .and_then(|code| StatusCode::from_u16(code as u16).ok())
+        .unwrap_or(StatusCode::OK);
+
+    let mut builder = Response::builder().status(status);
+
+    for pair in stored.response_headers.unwrap_or_default() {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(pair.name.as_bytes()),
+            HeaderValue::from_bytes(&pair.value),
+        ) {
+            builder = builder.header(name, value);
+        }
+    }
+
+    builder
+        .body(Body::from(stored.response_body.unwrap_or_default()))
+        .map_err(|err| Error::Other(err.to_string()))
+}
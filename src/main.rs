@@ -1,6 +1,8 @@
 use std::error::Error;
+use std::sync::Arc;
 
 use axum::{
+    extract::FromRef,
     middleware,
     routing::{get, patch, post},
     Router,
@@ -8,15 +10,65 @@ use axum::{
 use axum_prometheus::PrometheusMetricLayer;
 use dotenvy::dotenv;
 use routes::health;
+use sqids::Sqids;
 use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{fmt::layer, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-use crate::auth::auth;
+use crate::auth::{auth, login, register, Config};
 use crate::routes::{create_link, get_link_statistics, redirect, update_link};
 mod auth;
+mod idempotency;
 mod routes;
 mod utils;
+mod worker;
+
+/// Shared application state handed to every handler through `with_state`.
+///
+/// The `FromRef` impl below lets a handler that only needs the database take
+/// `State<PgPool>` instead of the whole state.
+#[derive(Clone)]
+pub struct AppState {
+    pub db: PgPool,
+    pub sqids: Arc<Sqids>,
+    pub config: Arc<Config>,
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+/// Build the Sqids encoder from the environment.
+///
+/// `SQIDS_ALPHABET` is a shuffled alphabet, `SQIDS_MIN_LENGTH` pads short
+/// encodings, and `SQIDS_BLOCKLIST` (comma separated) forces any slug that
+/// would spell a listed word to be re-encoded until it is clean.
+fn build_sqids() -> Result<Sqids, Box<dyn Error>> {
+    let mut builder = Sqids::builder();
+
+    if let Ok(alphabet) = std::env::var("SQIDS_ALPHABET") {
+        builder = builder.alphabet(alphabet.chars().collect());
+    }
+
+    if let Ok(min_length) = std::env::var("SQIDS_MIN_LENGTH") {
+        builder = builder.min_length(min_length.parse()?);
+    }
+
+    if let Ok(blocklist) = std::env::var("SQIDS_BLOCKLIST") {
+        builder = builder.blocklist(
+            blocklist
+                .split(',')
+                .map(|word| word.trim().to_string())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        );
+    }
+
+    Ok(builder.build()?)
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -38,23 +90,35 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with(layer())
         .init();
 
+    worker::spawn(db.clone());
+
+    let sqids = build_sqids().expect("Failed to build Sqids encoder");
+
+    let state = AppState {
+        db: db.clone(),
+        sqids: Arc::new(sqids),
+        config: Arc::new(Config::from_env()),
+    };
+
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
 
     let app = Router::new()
         .route("/create", post(create_link))
         .route("/:id/statistics", get(get_link_statistics))
-        .route_layer(middleware::from_fn_with_state(db.clone(), auth))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth))
         .route(
             "/:id",
             patch(update_link)
-                .route_layer(middleware::from_fn_with_state(db.clone(), auth))
+                .route_layer(middleware::from_fn_with_state(state.clone(), auth))
                 .get(redirect),
         )
+        .route("/register", post(register))
+        .route("/login", post(login))
         .route("/metrics", get(|| async move { metric_handle.render() }))
         .route("/health", get(health))
         .layer(TraceLayer::new_for_http())
         .layer(prometheus_layer)
-        .with_state(db);
+        .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
         .await
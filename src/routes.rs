@@ -1,18 +1,18 @@
 use axum::{
     body::Body,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::HeaderMap,
     response::{IntoResponse, Response},
-    Json,
+    Extension, Json,
 };
-use base64::{engine::general_purpose, Engine};
-use rand::Rng;
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
-use serde::Serialize;
-use sqlx::PgPool;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::utils::internal_error;
+use crate::auth::AuthUser;
+use crate::utils::{Error, Result};
+use crate::AppState;
 
 const DEFAULT_CACHE_CONTROL_HEADER_VALUE: &str =
     "public, max-age=300, s-maxage=300, stale-while-revalidate=300, stale-if-error=300";
@@ -30,110 +30,271 @@ pub struct LinkTarget {
     pub target_url: String,
 }
 
-#[derive(serde::Serialize)]
+/// Time granularity for the click-over-time buckets.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+impl Granularity {
+    /// The `date_trunc` field name this granularity maps to.
+    fn as_date_trunc(&self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+            Granularity::Week => "week",
+        }
+    }
+}
+
+/// Window and granularity selectors for the statistics endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatisticsQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub granularity: Granularity,
+}
+
+#[derive(Serialize)]
+pub struct ClickBucket {
+    pub ts: DateTime<Utc>,
+    pub amount: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CountedValue {
+    pub value: Option<String>,
+    pub amount: i64,
+}
+
+#[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct CountedLinkStatistic {
-    pub amount: Option<i64>,
-    pub referer: Option<String>,
-    pub user_agent: Option<String>,
+pub struct LinkStatistics {
+    pub total: i64,
+    pub buckets: Vec<ClickBucket>,
+    pub top_referers: Vec<CountedValue>,
+    pub top_user_agents: Vec<CountedValue>,
 }
 
 pub async fn get_link_statistics(
-    State(pool): State<PgPool>,
-    Path(link_id): Path<String>,
-) -> Result<Json<Vec<CountedLinkStatistic>>, (StatusCode, String)> {
-    let fetch_statistics_timeout = tokio::time::Duration::from_millis(300);
-
-    let statistics = tokio::time::timeout(
-        fetch_statistics_timeout,
-        sqlx::query_as!(
-            CountedLinkStatistic,
-            r#"
-            select count(*) as amount, referer, user_agent from link_statistics group by link_id, referer, user_agent having link_id = $1
-            "#,
-            &link_id
-        )
-        .fetch_all(&pool)
+    State(state): State<AppState>,
+    Extension(AuthUser(owner_id)): Extension<AuthUser>,
+    Path(link_slug): Path<String>,
+    Query(query): Query<StatisticsQuery>,
+) -> Result<Json<LinkStatistics>> {
+    let link_id = decode_slug(&state.sqids, &link_slug)?;
+
+    // Only the owner may read a link's statistics; a foreign id is a 404.
+    sqlx::query_scalar!(
+        "select id from links where id = $1 and owner_id = $2",
+        link_id,
+        owner_id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let granularity = query.granularity.as_date_trunc();
+
+    let total = sqlx::query_scalar!(
+        r#"
+        select count(*) as "amount!"
+        from link_statistics
+        where link_id = $1
+          and ($2::timestamptz is null or created_at >= $2)
+          and ($3::timestamptz is null or created_at < $3)
+        "#,
+        link_id,
+        query.from,
+        query.to
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    let buckets = sqlx::query_as!(
+        ClickBucket,
+        r#"
+        select date_trunc($1, created_at) as "ts!", count(*) as "amount!"
+        from link_statistics
+        where link_id = $2
+          and ($3::timestamptz is null or created_at >= $3)
+          and ($4::timestamptz is null or created_at < $4)
+        group by 1
+        order by 1
+        "#,
+        granularity,
+        link_id,
+        query.from,
+        query.to
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let top_referers = sqlx::query_as!(
+        CountedValue,
+        r#"
+        select referer as value, count(*) as "amount!"
+        from link_statistics
+        where link_id = $1
+          and ($2::timestamptz is null or created_at >= $2)
+          and ($3::timestamptz is null or created_at < $3)
+        group by referer
+        order by count(*) desc
+        limit 10
+        "#,
+        link_id,
+        query.from,
+        query.to
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let top_user_agents = sqlx::query_as!(
+        CountedValue,
+        r#"
+        select user_agent as value, count(*) as "amount!"
+        from link_statistics
+        where link_id = $1
+          and ($2::timestamptz is null or created_at >= $2)
+          and ($3::timestamptz is null or created_at < $3)
+        group by user_agent
+        order by count(*) desc
+        limit 10
+        "#,
+        link_id,
+        query.from,
+        query.to
     )
-    .await
-    .map_err(internal_error)?
-    .map_err(internal_error)?;
+    .fetch_all(&state.db)
+    .await?;
 
-    tracing::debug!("Statistics for link with id {} requested", link_id);
+    tracing::debug!("Statistics for link with id {} requested", link_slug);
 
-    Ok(Json(statistics))
+    Ok(Json(LinkStatistics {
+        total,
+        buckets,
+        top_referers,
+        top_user_agents,
+    }))
 }
 
-fn generate_id() -> String {
-    let random_number = rand::thread_rng().gen_range(0..u32::MAX);
-    general_purpose::URL_SAFE_NO_PAD.encode(random_number.to_string())
+/// Decode a short slug back into the `links.id` it was minted from.
+///
+/// `decode` is not canonical — several non-canonical strings decode to the same
+/// integer — so we re-encode the result and require it to round-trip back to the
+/// slug we were given. That keeps the one-slug-per-id guarantee and stops crafted
+/// input from resolving to arbitrary links. An unknown, malformed, or
+/// non-canonical slug is surfaced as a 404 so callers can't tell a bad code apart
+/// from a deleted link.
+fn decode_slug(sqids: &sqids::Sqids, slug: &str) -> Result<i64> {
+    let id = *sqids.decode(slug).first().ok_or(Error::NotFound)?;
+
+    if sqids.encode(&[id]).ok().as_deref() != Some(slug) {
+        return Err(Error::NotFound);
+    }
+
+    Ok(id as i64)
 }
 
 pub async fn create_link(
-    State(db): State<sqlx::PgPool>,
+    State(state): State<AppState>,
+    Extension(AuthUser(owner_id)): Extension<AuthUser>,
+    headers: HeaderMap,
     Json(new_link): Json<LinkTarget>,
-) -> Result<Json<Link>, (StatusCode, String)> {
-    let url = Url::parse(&new_link.target_url)
-        .map_err(|_| (StatusCode::CONFLICT, "url malformed".into()))?
-        .to_string();
+) -> Result<Response> {
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    // A repeat of a known key short-circuits before we touch `links`.
+    if let Some(key) = &idempotency_key {
+        if let Some(stored) = crate::idempotency::begin(&state.db, owner_id, key).await? {
+            return crate::idempotency::replay(stored);
+        }
+    }
+
+    // If any step below fails once a key has been claimed we must release the
+    // pending row, otherwise every future retry of that key wedges on a 409.
+    let result = build_link(&state, owner_id, &new_link).await;
+
+    match (&idempotency_key, result) {
+        (Some(key), Ok(response)) => {
+            crate::idempotency::save(&state.db, owner_id, key, response).await
+        }
+        (Some(key), Err(err)) => {
+            crate::idempotency::abort(&state.db, owner_id, key).await;
+            Err(err)
+        }
+        (None, result) => result,
+    }
+}
 
-    let new_id = generate_id();
+/// Mint a short link, producing the response the caller ultimately returns.
+async fn build_link(state: &AppState, owner_id: i64, new_link: &LinkTarget) -> Result<Response> {
+    let url = Url::parse(&new_link.target_url)?.to_string();
 
     let insert_link_timeout = tokio::time::Duration::from_millis(300);
 
-    let new_link = tokio::time::timeout(
+    let new_id = tokio::time::timeout(
         insert_link_timeout,
-        sqlx::query_as!(
-            Link,
+        sqlx::query_scalar!(
             r#"
-            with inserted_link as (
-                INSERT INTO links (id, target_url)
-                VALUES ($1, $2)
-                RETURNING id, target_url
-            )
-            SELECT id, target_url FROM inserted_link
-        "#,
-            &new_id,
-            &url
+            INSERT INTO links (target_url, owner_id)
+            VALUES ($1, $2)
+            RETURNING id
+            "#,
+            &url,
+            owner_id
         )
-        .fetch_one(&db),
+        .fetch_one(&state.db),
     )
-    .await
-    .map_err(internal_error)?
-    .map_err(internal_error)?;
+    .await??;
+
+    let slug = state
+        .sqids
+        .encode(&[new_id as u64])
+        .map_err(|err| Error::Other(err.to_string()))?;
 
-    tracing::debug!("Created link id {} for {}", new_id, url);
+    tracing::debug!("Created link id {} ({}) for {}", new_id, slug, url);
 
-    Ok(Json(new_link))
+    Ok(Json(Link {
+        id: slug,
+        target_url: url,
+    })
+    .into_response())
 }
 
 pub async fn redirect(
-    State(db): State<sqlx::PgPool>,
+    State(state): State<AppState>,
     Path(requested_link): Path<String>,
     headers: HeaderMap,
-) -> Result<Response, (StatusCode, String)> {
+) -> Result<Response> {
+    let link_id = decode_slug(&state.sqids, &requested_link)?;
+
     let timeout = tokio::time::Duration::from_millis(300);
 
-    let request = sqlx::query_as!(
-        Link,
-        "SELECT id, target_url FROM links WHERE id = $1",
-        requested_link
+    let request = sqlx::query_scalar!(
+        "SELECT target_url FROM links WHERE id = $1",
+        link_id
     )
-    .fetch_optional(&db);
+    .fetch_optional(&state.db);
 
     let link_timeout = tokio::time::timeout(timeout, request);
 
-    let link: Link = link_timeout
-        .await
-        .map_err(internal_error)?
-        .map_err(internal_error)?
-        .ok_or_else(|| "Not Found".to_string())
-        .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    let target_url: String = link_timeout.await??.ok_or(Error::NotFound)?;
 
     tracing::debug!(
         "Redirecting link id {} to {}",
         requested_link,
-        link.target_url
+        target_url
     );
 
     let referer_header = headers
@@ -144,31 +305,31 @@ pub async fn redirect(
         .get("user-agent")
         .map(|value| value.to_str().unwrap_or_default().to_string());
 
-    let insert_statistics_timeout = tokio::time::Duration::from_millis(300);
+    let enqueue_click_timeout = tokio::time::Duration::from_millis(300);
 
-    let saved_statistic = tokio::time::timeout(
-        insert_statistics_timeout,
+    let enqueued_click = tokio::time::timeout(
+        enqueue_click_timeout,
         sqlx::query(
             r#"
-                insert into link_statistics(link_id, referer, user_agent)
+                insert into click_queue(link_id, referer, user_agent)
                 values($1, $2, $3)
                 "#,
         )
-        .bind(&requested_link)
+        .bind(link_id)
         .bind(&referer_header)
         .bind(&user_agent_header)
-        .execute(&db),
+        .execute(&state.db),
     )
     .await;
 
-    match saved_statistic {
-        Err(elapsed) => tracing::error!("Saving new link click resulted in a timeout: {}", elapsed),
+    match enqueued_click {
+        Err(elapsed) => tracing::error!("Enqueuing new link click resulted in a timeout: {}", elapsed),
         Ok(Err(err)) => tracing::error!(
-            "Saving a new link click failed with the following error: {}",
+            "Enqueuing a new link click failed with the following error: {}",
             err
         ),
         _ => tracing::debug!(
-            "Persisted new link click for link with id {}, referer {}, and user_agent {}",
+            "Enqueued new link click for link with id {}, referer {}, and user_agent {}",
             requested_link,
             referer_header.unwrap_or_default(),
             user_agent_header.unwrap_or_default()
@@ -177,7 +338,7 @@ pub async fn redirect(
 
     Ok(Response::builder()
         .status(StatusCode::TEMPORARY_REDIRECT)
-        .header("Location", link.target_url)
+        .header("Location", target_url)
         .header("Cache-Control", DEFAULT_CACHE_CONTROL_HEADER_VALUE)
         .body(Body::empty())
         .expect("Failed to build response"))
@@ -188,38 +349,43 @@ pub async fn health() -> impl IntoResponse {
 }
 
 pub async fn update_link(
-    State(pool): State<PgPool>,
-    Path(link_id): Path<String>,
+    State(state): State<AppState>,
+    Extension(AuthUser(owner_id)): Extension<AuthUser>,
+    Path(link_slug): Path<String>,
     Json(update_link): Json<LinkTarget>,
-) -> Result<Json<Link>, (StatusCode, String)> {
-    let url = Url::parse(&update_link.target_url)
-        .map_err(|_| (StatusCode::CONFLICT, "url malformed".into()))?
-        .to_string();
+) -> Result<Json<Link>> {
+    let url = Url::parse(&update_link.target_url)?.to_string();
+
+    let link_id = decode_slug(&state.sqids, &link_slug)?;
 
     let update_link_timeout = tokio::time::Duration::from_millis(300);
 
-    let link = tokio::time::timeout(
+    // Scope the update to the caller's own links; a foreign id matches no row
+    // and surfaces as a 404 rather than silently rewriting someone else's link.
+    tokio::time::timeout(
         update_link_timeout,
-        sqlx::query_as!(
-            Link,
+        sqlx::query_scalar!(
             r#"
             with updated_link as (
-                update links set target_url = $1 where id = $2
-                returning id, target_url
+                update links set target_url = $1 where id = $2 and owner_id = $3
+                returning id
             )
-            select id, target_url
+            select id
             from updated_link
             "#,
             &url,
-            &link_id
+            link_id,
+            owner_id
         )
-        .fetch_one(&pool),
+        .fetch_optional(&state.db),
     )
-    .await
-    .map_err(internal_error)?
-    .map_err(internal_error)?;
+    .await??
+    .ok_or(Error::NotFound)?;
 
-    tracing::debug!("Updated link with id {}, now targeting {}", link_id, url);
+    tracing::debug!("Updated link with id {}, now targeting {}", link_slug, url);
 
-    Ok(Json(link))
+    Ok(Json(Link {
+        id: link_slug,
+        target_url: url,
+    }))
 }
@@ -1,15 +1,66 @@
-use axum::http::StatusCode;
-use metrics::{counter, Counter};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use metrics::counter;
+use serde_json::json;
+use thiserror::Error;
 
-pub fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    tracing::error!("{}", err);
+/// The one error type every handler returns.
+///
+/// `#[from]` conversions let handlers use `?` directly on the fallible calls
+/// they make, and the `IntoResponse` impl renders a consistent JSON body while
+/// keeping the `internal_error` Prometheus counter firing for 5xx responses.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("database error")]
+    Database(#[from] sqlx::Error),
+    #[error("operation timed out")]
+    Timeout(#[from] tokio::time::error::Elapsed),
+    #[error("not found")]
+    NotFound,
+    #[error("url malformed")]
+    MalformedUrl(#[from] url::ParseError),
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("a request with this idempotency key is already in progress")]
+    IdempotencyConflict,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Database(_) | Error::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::MalformedUrl(_) | Error::IdempotencyConflict => StatusCode::CONFLICT,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
 
-    let labels = [("error", format!("{}", err))];
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
 
-    counter!("internal_error", &labels);
+        if status.is_server_error() {
+            tracing::error!("{}", self);
 
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+            let labels = [("error", format!("{}", self))];
+            counter!("internal_error", &labels);
+        }
+
+        let body = Json(json!({
+            "error": self.to_string(),
+            "status": status.as_u16(),
+        }));
+
+        (status, body).into_response()
+    }
 }
+
+/// Handler result alias so signatures stay `Result<T>` across the crate.
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+/// How many queued clicks a single worker pass drains.
+const BATCH_SIZE: i64 = 100;
+/// How long to wait before polling again when the queue is empty.
+const IDLE_SLEEP: Duration = Duration::from_millis(500);
+/// Backoff applied after a whole batch fails (e.g. the database is unreachable).
+const ERROR_BACKOFF: Duration = Duration::from_secs(5);
+/// How many times a single click is retried before it is treated as dead and
+/// left in the queue for inspection rather than reattempted forever.
+const MAX_RETRIES: i32 = 5;
+/// Base of the per-row exponential backoff, in seconds: a row attempted
+/// `n_retries` times is skipped until `2.pow(n_retries)` of these have elapsed.
+const RETRY_BACKOFF_BASE_SECS: f64 = 2.0;
+
+/// Spawn the background click-delivery worker.
+///
+/// The loop drains `click_queue` into `link_statistics` forever; multiple
+/// workers can run concurrently because each batch is claimed with
+/// `FOR UPDATE SKIP LOCKED`.
+pub fn spawn(db: PgPool) {
+    tokio::spawn(async move {
+        loop {
+            match drain_batch(&db).await {
+                Ok(0) => tokio::time::sleep(IDLE_SLEEP).await,
+                Ok(n) => tracing::debug!("Flushed {} queued click(s) to link_statistics", n),
+                Err(err) => {
+                    tracing::error!("Click worker batch failed: {}", err);
+                    tokio::time::sleep(ERROR_BACKOFF).await;
+                }
+            }
+        }
+    });
+}
+
+/// Drain one batch, returning how many clicks were persisted.
+async fn drain_batch(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    // Skip rows that have exhausted their retries (left as an informal dead
+    // letter) and ones still inside their exponential backoff window, so a
+    // single poison row can't block the rest of the queue.
+    let rows = sqlx::query!(
+        r#"
+        select id, link_id, referer, user_agent, enqueued_at
+        from click_queue
+        where n_retries < $2
+          and (
+              last_attempt_at is null
+              or last_attempt_at
+                 < now() - make_interval(secs => $3 * power(2.0, n_retries))
+          )
+        order by enqueued_at
+        for update skip locked
+        limit $1
+        "#,
+        BATCH_SIZE,
+        MAX_RETRIES,
+        RETRY_BACKOFF_BASE_SECS
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if rows.is_empty() {
+        tx.commit().await?;
+        return Ok(0);
+    }
+
+    let mut persisted = Vec::with_capacity(rows.len());
+    for row in &rows {
+        // Each insert runs inside its own SAVEPOINT (a nested transaction). A
+        // poison row — FK mismatch, an invalid byte in a captured header —
+        // aborts only its savepoint, which we roll back; the outer `tx` stays
+        // usable so the remaining clicks, the delete, and the retry bump still
+        // commit instead of the whole batch wedging the queue forever.
+        let mut sp = tx.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            insert into link_statistics (link_id, referer, user_agent, created_at)
+            values ($1, $2, $3, $4)
+            "#,
+            row.link_id,
+            row.referer,
+            row.user_agent,
+            row.enqueued_at
+        )
+        .execute(&mut *sp)
+        .await;
+
+        match result {
+            Ok(_) => {
+                sp.commit().await?;
+                persisted.push(row.id);
+            }
+            Err(err) => {
+                sp.rollback().await?;
+                tracing::error!("Failed to persist queued click {}: {}", row.id, err);
+            }
+        }
+    }
+
+    if !persisted.is_empty() {
+        sqlx::query!("delete from click_queue where id = any($1)", &persisted)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // Anything we couldn't persist stays queued with a bumped retry counter and
+    // a fresh attempt timestamp, so the backoff window above delays its next
+    // pass and it eventually ages out past `MAX_RETRIES`.
+    let failed: Vec<i64> = rows
+        .iter()
+        .map(|row| row.id)
+        .filter(|id| !persisted.contains(id))
+        .collect();
+
+    if !failed.is_empty() {
+        sqlx::query!(
+            r#"
+            update click_queue
+            set n_retries = n_retries + 1,
+                last_attempt_at = now()
+            where id = any($1)
+            "#,
+            &failed
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(persisted.len() as u64)
+}